@@ -1,6 +1,29 @@
-use std::io;
+//! Cargo.toml requirements for this module: `tracing` as a direct dependency
+//! (used for slow-request warnings), `tokio` with at least the `sync`,
+//! `time`, and `io-util` features (the `OnceCell`/`RwLock`, `time::timeout`,
+//! and `AsyncRead` usage below respectively), and the optional `zstd`/`lz4`
+//! features each gating their matching [`Codec`] variant's decompression.
+//! The `unstable-pretrained-model-stream` feature additionally gates
+//! [`Connection::get_pretrained_model_stream`] and
+//! [`NegotiatedConnection::get_pretrained_model_stream`], which have no
+//! server responder in this tree yet (see those methods' docs).
 
-use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashSet,
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    sync::{OnceCell, RwLock},
+};
 
 use super::Connection;
 use crate::{
@@ -9,6 +32,300 @@ use crate::{
     unary_request,
 };
 
+/// The protocol version this client implementation speaks.
+///
+/// Two versions are compatible if their `major` components match; a `minor`
+/// bump only signals additive, backward-compatible changes to the schema.
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion { major: 1, minor: 0 };
+
+/// Request code reserved for the handshake performed by [`Connection::negotiate`].
+///
+/// Kept outside [`server::RequestCode`] so it can never collide with a
+/// future, server-defined request code.
+const NEGOTIATE_REQUEST_CODE: u32 = u32::MAX;
+
+/// Request code reserved for [`Connection::get_pretrained_model_stream`], for
+/// the same reason as [`NEGOTIATE_REQUEST_CODE`]: it must never collide with
+/// a server-defined [`server::RequestCode`].
+#[cfg(feature = "unstable-pretrained-model-stream")]
+const GET_PRETRAINED_MODEL_STREAM_REQUEST_CODE: u32 = u32::MAX - 1;
+
+/// A protocol version exchanged during the connection handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl ProtocolVersion {
+    fn is_compatible_with(self, other: Self) -> bool {
+        self.major == other.major
+    }
+}
+
+/// The handshake request sent by [`Connection::negotiate`]: this client's
+/// protocol version and the codecs it is able to decode for
+/// [`Connection::get_pretrained_model_stream`] (see
+/// [`NegotiatedProtocol::stream_codec`] for why the name is this specific),
+/// so the server can pick one this client can actually use.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NegotiationRequest {
+    version: ProtocolVersion,
+    supported_stream_codecs: Vec<Codec>,
+}
+
+/// An optional server capability, advertised during negotiation so the client
+/// can fail a single unsupported method instead of misinterpreting whatever
+/// comes back on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Capability {
+    GetTorExitNodeList,
+}
+
+/// The outcome of a successful handshake: the server's protocol version, the
+/// set of optional capabilities it advertises, and the codec it compresses
+/// [`Connection::get_pretrained_model_stream`] chunks with.
+///
+/// The field is deliberately named `stream_codec`, not `codec`: it governs
+/// only that one streamed response. The rest of the client's methods go
+/// through [`unary_request`], whose request/response framing is opaque to
+/// this module — there is no point in this layer at which a compressed body
+/// could be intercepted and decompressed before `unary_request` deserializes
+/// it. Compressing `get_tidb_patterns`, the network-group lists, or the
+/// buffered `get_pretrained_model` is out of scope until `unary_request`
+/// itself grows codec support; this struct is not where that would land.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NegotiatedProtocol {
+    pub version: ProtocolVersion,
+    pub capabilities: HashSet<Capability>,
+    pub stream_codec: Codec,
+}
+
+impl NegotiatedProtocol {
+    fn supports(&self, cap: Capability) -> bool {
+        self.capabilities.contains(&cap)
+    }
+}
+
+/// A response-compression codec negotiated between client and server, applied
+/// to [`PretrainedModelStream`] chunks (see [`NegotiatedProtocol`] for why the
+/// scope stops there).
+///
+/// `Identity` (no compression) is always supported and is used whenever
+/// either peer doesn't opt into a real codec, so older peers keep working.
+///
+/// The variants themselves are always present on the wire regardless of
+/// cargo features, so a client built without, say, the `zstd` feature can
+/// still deserialize a [`NegotiatedProtocol`] naming [`Codec::Zstd`] instead
+/// of failing the handshake outright; only the ability to actually decode
+/// that codec is feature-gated, in [`Codec::decompress`]. [`Connection::negotiate`]
+/// advertises this client's decodable codecs so a well-behaved server
+/// shouldn't pick one it can't decode in the first place, and
+/// [`Connection::negotiate`] additionally falls back to [`Codec::Identity`]
+/// if the server ignores that and picks one anyway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum Codec {
+    #[default]
+    Identity,
+    Zstd,
+    Lz4,
+}
+
+impl Codec {
+    /// The codecs this build can decompress, most preferred first, always
+    /// ending with [`Codec::Identity`] since it is never feature-gated.
+    ///
+    /// Advertised to the server during [`Connection::negotiate`] so it can
+    /// pick a codec this client can actually decode.
+    fn supported() -> Vec<Self> {
+        #[allow(unused_mut)]
+        let mut codecs = Vec::new();
+        #[cfg(feature = "zstd")]
+        codecs.push(Codec::Zstd);
+        #[cfg(feature = "lz4")]
+        codecs.push(Codec::Lz4);
+        codecs.push(Codec::Identity);
+        codecs
+    }
+
+    /// Decompresses `bytes` received under this codec, or returns them
+    /// unchanged for [`Codec::Identity`].
+    ///
+    /// Bounds the *decompressed* size at [`MAX_DECOMPRESSED_CHUNK_LEN`], not
+    /// just the compressed input: [`MAX_CHUNK_LEN`] alone only bounds the
+    /// wire-sized chunk handed to this function, and a compressed payload
+    /// that size can still expand to an arbitrarily large buffer (a
+    /// "decompression bomb"). Returns [`RequestError::Decode`] if the
+    /// decompressed output would exceed that bound.
+    ///
+    /// Returns [`RequestError::Unsupported`] for a codec this build wasn't
+    /// compiled to decode (its cargo feature is disabled).
+    fn decompress(self, bytes: Vec<u8>) -> Result<Vec<u8>, RequestError> {
+        match self {
+            Codec::Identity => Ok(bytes),
+            Codec::Zstd => {
+                #[cfg(feature = "zstd")]
+                {
+                    zstd::bulk::decompress(&bytes, MAX_DECOMPRESSED_CHUNK_LEN).map_err(decode_error)
+                }
+                #[cfg(not(feature = "zstd"))]
+                {
+                    Err(RequestError::Unsupported)
+                }
+            }
+            Codec::Lz4 => {
+                #[cfg(feature = "lz4")]
+                {
+                    lz4::block::decompress(&bytes, Some(MAX_DECOMPRESSED_CHUNK_LEN as i32))
+                        .map_err(decode_error)
+                }
+                #[cfg(not(feature = "lz4"))]
+                {
+                    Err(RequestError::Unsupported)
+                }
+            }
+        }
+    }
+}
+
+/// Wraps a codec's own error type as a [`RequestError::Decode`], mirroring
+/// how [`classify_request_error`] handles framing errors from the transport.
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+fn decode_error(e: impl std::fmt::Display) -> RequestError {
+    RequestError::Decode(bincode::Error::from(bincode::ErrorKind::Custom(
+        e.to_string(),
+    )))
+}
+
+/// An error from [`Connection::negotiate`].
+#[derive(Debug)]
+pub enum NegotiationError {
+    /// The handshake request itself failed (transport or (de)serialization error).
+    Request(io::Error),
+    /// The server's major protocol version is incompatible with this client's.
+    IncompatibleVersion {
+        ours: ProtocolVersion,
+        theirs: ProtocolVersion,
+    },
+}
+
+impl fmt::Display for NegotiationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Request(e) => write!(f, "handshake request failed: {e}"),
+            Self::IncompatibleVersion { ours, theirs } => write!(
+                f,
+                "incompatible protocol version: ours={}.{}, server's={}.{}",
+                ours.major, ours.minor, theirs.major, theirs.minor
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NegotiationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Request(e) => Some(e),
+            Self::IncompatibleVersion { .. } => None,
+        }
+    }
+}
+
+impl From<NegotiationError> for io::Error {
+    fn from(e: NegotiationError) -> Self {
+        match e {
+            NegotiationError::Request(e) => e,
+            e @ NegotiationError::IncompatibleVersion { .. } => {
+                io::Error::new(io::ErrorKind::Other, e.to_string())
+            }
+        }
+    }
+}
+
+impl From<NegotiationError> for RequestError {
+    fn from(e: NegotiationError) -> Self {
+        match e {
+            NegotiationError::Request(e) => classify_request_error(e),
+            NegotiationError::IncompatibleVersion { .. } => RequestError::Unsupported,
+        }
+    }
+}
+
+/// A structured error from a client request, preserving the cause instead of
+/// collapsing everything into a stringified [`io::Error`].
+#[derive(Debug)]
+pub enum RequestError {
+    /// The underlying QUIC connection or stream failed.
+    Transport(io::Error),
+    /// The response could not be deserialized into the expected type.
+    Decode(bincode::Error),
+    /// The server reported an application-level error.
+    Server(String),
+    /// The requested object does not exist on the server.
+    NotFound,
+    /// The server does not support this request.
+    Unsupported,
+    /// The server's response doesn't match what was requested, even after a
+    /// bounded re-fetch (e.g. a [`DataSource`] whose id or name differs from
+    /// the [`DataSourceKey`] that was requested).
+    IdentityMismatch,
+    /// The request didn't complete within its deadline.
+    Timeout,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Transport(e) => write!(f, "transport error: {e}"),
+            Self::Decode(e) => write!(f, "failed to decode response: {e}"),
+            Self::Server(e) => write!(f, "server error: {e}"),
+            Self::NotFound => write!(f, "requested object not found"),
+            Self::Unsupported => write!(f, "server does not support this request"),
+            Self::IdentityMismatch => write!(f, "server response doesn't match the request"),
+            Self::Timeout => write!(f, "request timed out"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Transport(e) => Some(e),
+            Self::Decode(e) => Some(e),
+            Self::Server(_) | Self::NotFound | Self::Unsupported | Self::IdentityMismatch | Self::Timeout => {
+                None
+            }
+        }
+    }
+}
+
+/// Back-compat shim for callers still matching on [`io::Error`].
+impl From<RequestError> for io::Error {
+    fn from(e: RequestError) -> Self {
+        match e {
+            RequestError::Transport(e) => e,
+            RequestError::NotFound => io::Error::from(io::ErrorKind::NotFound),
+            RequestError::Unsupported => io::Error::from(io::ErrorKind::Unsupported),
+            RequestError::Timeout => io::Error::from(io::ErrorKind::TimedOut),
+            e @ (RequestError::Decode(_) | RequestError::Server(_) | RequestError::IdentityMismatch) => {
+                io::Error::new(io::ErrorKind::Other, e.to_string())
+            }
+        }
+    }
+}
+
+/// Classifies an [`io::Error`] returned by the transport layer as either a
+/// [`RequestError::Transport`] failure or, if it wraps a deserialization
+/// failure, a [`RequestError::Decode`] error.
+fn classify_request_error(err: io::Error) -> RequestError {
+    if is_transport_error(&err) {
+        return RequestError::Transport(err);
+    }
+    RequestError::Decode(bincode::Error::from(bincode::ErrorKind::Custom(
+        err.to_string(),
+    )))
+}
+
 /// The client API.
 impl Connection {
     /// Fetches the configuration from the server.
@@ -18,9 +335,20 @@ impl Connection {
     /// # Errors
     ///
     /// Returns an error if the request fails or the response is invalid.
-    pub async fn get_config(&self) -> io::Result<String> {
-        let res: Result<String, String> = request(self, server::RequestCode::GetConfig, ()).await?;
-        res.map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    pub async fn get_config(&self) -> Result<String, RequestError> {
+        self.get_config_with_options(RequestOptions::default()).await
+    }
+
+    /// Like [`Connection::get_config`], but `options` overrides the default
+    /// per-request timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the response is invalid.
+    pub async fn get_config_with_options(&self, options: RequestOptions) -> Result<String, RequestError> {
+        let res: Result<String, String> =
+            request_with_options(self, server::RequestCode::GetConfig, (), options).await?;
+        res.map_err(RequestError::Server)
     }
 
     /// Fetches the list of allowed networks from the server.
@@ -28,10 +356,23 @@ impl Connection {
     /// # Errors
     ///
     /// Returns an error if the request fails or the response is invalid.
-    pub async fn get_allow_list(&self) -> io::Result<HostNetworkGroup> {
+    pub async fn get_allow_list(&self) -> Result<HostNetworkGroup, RequestError> {
+        self.get_allow_list_with_options(RequestOptions::default()).await
+    }
+
+    /// Like [`Connection::get_allow_list`], but `options` overrides the
+    /// default per-request timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the response is invalid.
+    pub async fn get_allow_list_with_options(
+        &self,
+        options: RequestOptions,
+    ) -> Result<HostNetworkGroup, RequestError> {
         let res: Result<HostNetworkGroup, String> =
-            request(self, server::RequestCode::GetAllowList, ()).await?;
-        res.map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            request_with_options(self, server::RequestCode::GetAllowList, (), options).await?;
+        res.map_err(RequestError::Server)
     }
 
     /// Fetches the list of blocked networks from the server.
@@ -39,22 +380,72 @@ impl Connection {
     /// # Errors
     ///
     /// Returns an error if the request fails or the response is invalid.
-    pub async fn get_block_list(&self) -> io::Result<HostNetworkGroup> {
+    pub async fn get_block_list(&self) -> Result<HostNetworkGroup, RequestError> {
+        self.get_block_list_with_options(RequestOptions::default()).await
+    }
+
+    /// Like [`Connection::get_block_list`], but `options` overrides the
+    /// default per-request timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the response is invalid.
+    pub async fn get_block_list_with_options(
+        &self,
+        options: RequestOptions,
+    ) -> Result<HostNetworkGroup, RequestError> {
         let res: Result<HostNetworkGroup, String> =
-            request(self, server::RequestCode::GetBlockList, ()).await?;
-        res.map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            request_with_options(self, server::RequestCode::GetBlockList, (), options).await?;
+        res.map_err(RequestError::Server)
     }
 
     /// Fetches a data source from the server.
     ///
     /// # Errors
     ///
-    /// Returns an error if the request fails or the response is invalid.
-    pub async fn get_data_source(&self, key: &DataSourceKey<'_>) -> io::Result<DataSource> {
+    /// Returns [`RequestError::NotFound`] if no data source matches `key`.
+    /// Returns [`RequestError::IdentityMismatch`] if the server's response
+    /// doesn't match `key` even after one bounded re-fetch (guarding against
+    /// a stale or misrouted response being accepted blindly). Returns
+    /// another [`RequestError`] if the request fails or the response is
+    /// invalid.
+    pub async fn get_data_source(&self, key: &DataSourceKey<'_>) -> Result<DataSource, RequestError> {
+        self.get_data_source_with_options(key, RequestOptions::default())
+            .await
+    }
+
+    /// Like [`Connection::get_data_source`], but `options` overrides the
+    /// default per-request timeout for both the initial fetch and the bounded
+    /// re-fetch on mismatch.
+    ///
+    /// # Errors
+    ///
+    /// See [`Connection::get_data_source`].
+    pub async fn get_data_source_with_options(
+        &self,
+        key: &DataSourceKey<'_>,
+        options: RequestOptions,
+    ) -> Result<DataSource, RequestError> {
+        let data_source = self.fetch_data_source(key, options).await?;
+        if data_source_matches(&data_source, key) {
+            return Ok(data_source);
+        }
+        let data_source = self.fetch_data_source(key, options).await?;
+        if data_source_matches(&data_source, key) {
+            Ok(data_source)
+        } else {
+            Err(RequestError::IdentityMismatch)
+        }
+    }
+
+    async fn fetch_data_source(
+        &self,
+        key: &DataSourceKey<'_>,
+        options: RequestOptions,
+    ) -> Result<DataSource, RequestError> {
         let res: Result<Option<DataSource>, String> =
-            request(self, server::RequestCode::GetDataSource, key).await?;
-        res.map_err(|e| io::Error::new(io::ErrorKind::Other, e))
-            .and_then(|res| res.ok_or_else(|| io::Error::from(io::ErrorKind::NotFound)))
+            request_with_options(self, server::RequestCode::GetDataSource, key, options).await?;
+        res.map_err(RequestError::Server)?.ok_or(RequestError::NotFound)
     }
 
     /// Fetches the list of internal networks from the server.
@@ -62,10 +453,24 @@ impl Connection {
     /// # Errors
     ///
     /// Returns an error if the request fails or the response is invalid.
-    pub async fn get_internal_network_list(&self) -> io::Result<HostNetworkGroup> {
+    pub async fn get_internal_network_list(&self) -> Result<HostNetworkGroup, RequestError> {
+        self.get_internal_network_list_with_options(RequestOptions::default())
+            .await
+    }
+
+    /// Like [`Connection::get_internal_network_list`], but `options`
+    /// overrides the default per-request timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the response is invalid.
+    pub async fn get_internal_network_list_with_options(
+        &self,
+        options: RequestOptions,
+    ) -> Result<HostNetworkGroup, RequestError> {
         let res: Result<HostNetworkGroup, String> =
-            request(self, server::RequestCode::GetInternalNetworkList, ()).await?;
-        res.map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            request_with_options(self, server::RequestCode::GetInternalNetworkList, (), options).await?;
+        res.map_err(RequestError::Server)
     }
 
     /// Fetches the patterns from the threat-intelligence database.
@@ -76,10 +481,25 @@ impl Connection {
     pub async fn get_tidb_patterns(
         &self,
         tidbs: &[(String, String)],
-    ) -> io::Result<Vec<(String, Option<crate::types::Tidb>)>> {
+    ) -> Result<Vec<(String, Option<crate::types::Tidb>)>, RequestError> {
+        self.get_tidb_patterns_with_options(tidbs, RequestOptions::default())
+            .await
+    }
+
+    /// Like [`Connection::get_tidb_patterns`], but `options` overrides the
+    /// default per-request timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the response is invalid.
+    pub async fn get_tidb_patterns_with_options(
+        &self,
+        tidbs: &[(String, String)],
+        options: RequestOptions,
+    ) -> Result<Vec<(String, Option<crate::types::Tidb>)>, RequestError> {
         let res: Result<Vec<(String, Option<crate::types::Tidb>)>, String> =
-            request(self, server::RequestCode::GetTidbPatterns, tidbs).await?;
-        res.map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            request_with_options(self, server::RequestCode::GetTidbPatterns, tidbs, options).await?;
+        res.map_err(RequestError::Server)
     }
 
     /// Fetches the list of Tor exit nodes from the server.
@@ -87,10 +507,24 @@ impl Connection {
     /// # Errors
     ///
     /// Returns an error if the request fails or the response is invalid.
-    pub async fn get_tor_exit_node_list(&self) -> io::Result<Vec<String>> {
+    pub async fn get_tor_exit_node_list(&self) -> Result<Vec<String>, RequestError> {
+        self.get_tor_exit_node_list_with_options(RequestOptions::default())
+            .await
+    }
+
+    /// Like [`Connection::get_tor_exit_node_list`], but `options` overrides
+    /// the default per-request timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the response is invalid.
+    pub async fn get_tor_exit_node_list_with_options(
+        &self,
+        options: RequestOptions,
+    ) -> Result<Vec<String>, RequestError> {
         let res: Result<Vec<String>, String> =
-            request(self, server::RequestCode::GetTorExitNodeList, ()).await?;
-        res.map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            request_with_options(self, server::RequestCode::GetTorExitNodeList, (), options).await?;
+        res.map_err(RequestError::Server)
     }
 
     /// Fetches the list of trusted domains from the server.
@@ -98,10 +532,24 @@ impl Connection {
     /// # Errors
     ///
     /// Returns an error if the request fails or the response is invalid.
-    pub async fn get_trusted_domain_list(&self) -> io::Result<Vec<String>> {
+    pub async fn get_trusted_domain_list(&self) -> Result<Vec<String>, RequestError> {
+        self.get_trusted_domain_list_with_options(RequestOptions::default())
+            .await
+    }
+
+    /// Like [`Connection::get_trusted_domain_list`], but `options` overrides
+    /// the default per-request timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the response is invalid.
+    pub async fn get_trusted_domain_list_with_options(
+        &self,
+        options: RequestOptions,
+    ) -> Result<Vec<String>, RequestError> {
         let res: Result<Vec<String>, String> =
-            request(self, server::RequestCode::GetTrustedDomainList, ()).await?;
-        res.map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            request_with_options(self, server::RequestCode::GetTrustedDomainList, (), options).await?;
+        res.map_err(RequestError::Server)
     }
 
     /// Fetches the list of trusted user agents from the server.
@@ -109,10 +557,24 @@ impl Connection {
     /// # Errors
     ///
     /// Returns an error if the request fails or the response is invalid.
-    pub async fn get_trusted_user_agent_list(&self) -> io::Result<Vec<String>> {
+    pub async fn get_trusted_user_agent_list(&self) -> Result<Vec<String>, RequestError> {
+        self.get_trusted_user_agent_list_with_options(RequestOptions::default())
+            .await
+    }
+
+    /// Like [`Connection::get_trusted_user_agent_list`], but `options`
+    /// overrides the default per-request timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the response is invalid.
+    pub async fn get_trusted_user_agent_list_with_options(
+        &self,
+        options: RequestOptions,
+    ) -> Result<Vec<String>, RequestError> {
         let res: Result<Vec<String>, String> =
-            request(self, server::RequestCode::GetTrustedUserAgentList, ()).await?;
-        res.map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            request_with_options(self, server::RequestCode::GetTrustedUserAgentList, (), options).await?;
+        res.map_err(RequestError::Server)
     }
 
     /// Fetches the pretrained model from the server.
@@ -120,10 +582,67 @@ impl Connection {
     /// # Errors
     ///
     /// Returns an error if the request fails or the response is invalid.
-    pub async fn get_pretrained_model(&self, name: &str) -> io::Result<Vec<u8>> {
+    pub async fn get_pretrained_model(&self, name: &str) -> Result<Vec<u8>, RequestError> {
+        self.get_pretrained_model_with_options(name, RequestOptions::default())
+            .await
+    }
+
+    /// Like [`Connection::get_pretrained_model`], but `options` overrides the
+    /// default per-request timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the response is invalid.
+    pub async fn get_pretrained_model_with_options(
+        &self,
+        name: &str,
+        options: RequestOptions,
+    ) -> Result<Vec<u8>, RequestError> {
         let res: Result<Vec<u8>, String> =
-            request(self, server::RequestCode::GetPretrainedModel, name).await?;
-        res.map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            request_with_options(self, server::RequestCode::GetPretrainedModel, name, options).await?;
+        res.map_err(RequestError::Server)
+    }
+
+    /// Streams the pretrained model `name` from the server in bounded,
+    /// length-delimited chunks instead of buffering the whole model in
+    /// memory, so callers can pipe it directly to disk with a fixed memory
+    /// ceiling.
+    ///
+    /// The server is expected to respond with a `u32` (big-endian) length
+    /// prefix followed by that many bytes, repeated, up to [`MAX_CHUNK_LEN`]
+    /// per chunk; a zero-length chunk marks the end of the model. The stream
+    /// must end with that zero-length chunk — a connection that closes
+    /// mid-frame, or without one, is a protocol violation and surfaces as
+    /// [`io::ErrorKind::UnexpectedEof`], not a clean end of stream.
+    ///
+    /// Gated behind the `unstable-pretrained-model-stream` feature: no
+    /// server in this tree answers [`GET_PRETRAINED_MODEL_STREAM_REQUEST_CODE`]
+    /// yet, so this is the client-side half of a wire contract with nothing
+    /// to complete it end-to-end. Do not enable the feature outside of
+    /// development against a server that implements the responder below.
+    ///
+    /// The server is expected to respond with a `u32` (big-endian) length
+    /// prefix followed by that many bytes, repeated, up to [`MAX_CHUNK_LEN`]
+    /// per chunk; a zero-length chunk marks the end of the model. The stream
+    /// must end with that zero-length chunk — a connection that closes
+    /// mid-frame, or without one, is a protocol violation and surfaces as
+    /// [`io::ErrorKind::UnexpectedEof`], not a clean end of stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails to send. Once streaming starts,
+    /// I/O errors surface through the returned reader instead, including
+    /// [`io::ErrorKind::InvalidData`] if a chunk's length prefix exceeds
+    /// [`MAX_CHUNK_LEN`] (rejected before the chunk is allocated) and
+    /// [`io::ErrorKind::UnexpectedEof`] if the connection closes before the
+    /// terminating zero-length chunk.
+    #[cfg(feature = "unstable-pretrained-model-stream")]
+    pub async fn get_pretrained_model_stream(
+        &self,
+        name: &str,
+    ) -> Result<PretrainedModelStream<impl AsyncRead + Unpin>, RequestError> {
+        let recv = open_pretrained_model_stream(self, name).await?;
+        Ok(PretrainedModelStream::new(recv, Codec::Identity))
     }
 
     /// Fetches the renew certificate from the server.
@@ -131,20 +650,650 @@ impl Connection {
     /// # Errors
     ///
     /// Returns an error if the request fails or the response is invalid.
-    pub async fn renew_certificate(&self, cert: &[u8]) -> io::Result<(String, String)> {
+    pub async fn renew_certificate(&self, cert: &[u8]) -> Result<(String, String), RequestError> {
+        self.renew_certificate_with_options(cert, RequestOptions::default())
+            .await
+    }
+
+    /// Like [`Connection::renew_certificate`], but `options` overrides the
+    /// default per-request timeout.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the response is invalid.
+    pub async fn renew_certificate_with_options(
+        &self,
+        cert: &[u8],
+        options: RequestOptions,
+    ) -> Result<(String, String), RequestError> {
         let res: Result<(String, String), String> =
-            request(self, server::RequestCode::RenewCertificate, cert).await?;
-        res.map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            request_with_options(self, server::RequestCode::RenewCertificate, cert, options).await?;
+        res.map_err(RequestError::Server)
+    }
+
+    /// Negotiates the protocol version, capability set, and response codec
+    /// with the server.
+    ///
+    /// This exchanges [`PROTOCOL_VERSION`] and this build's [`Codec::supported`]
+    /// list for the server's own version, advertised [`Capability`] set, and
+    /// chosen codec. Call this explicitly before relying on an optional
+    /// capability, or use [`NegotiatedConnection`], which performs the
+    /// handshake once on first use and caches the result.
+    ///
+    /// If the server picks a codec this client doesn't support despite the
+    /// advertised list (an uncooperative or buggy server), the returned
+    /// [`NegotiatedProtocol::stream_codec`] is downgraded to [`Codec::Identity`]
+    /// rather than trusting it, so [`PretrainedModelStream`] never hands a
+    /// caller a codec it can't decode.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`NegotiationError::Request`] if the handshake request fails,
+    /// or [`NegotiationError::IncompatibleVersion`] if the server's major
+    /// protocol version doesn't match this client's.
+    pub async fn negotiate(&self) -> Result<NegotiatedProtocol, NegotiationError> {
+        let (mut send, mut recv) = self.open_bi().await.map_err(NegotiationError::Request)?;
+        let ours = NegotiationRequest {
+            version: PROTOCOL_VERSION,
+            supported_stream_codecs: Codec::supported(),
+        };
+        let mut theirs: NegotiatedProtocol =
+            unary_request(&mut send, &mut recv, NEGOTIATE_REQUEST_CODE, ours)
+                .await
+                .map_err(NegotiationError::Request)?;
+        if !PROTOCOL_VERSION.is_compatible_with(theirs.version) {
+            return Err(NegotiationError::IncompatibleVersion {
+                ours: PROTOCOL_VERSION,
+                theirs: theirs.version,
+            });
+        }
+        if !Codec::supported().contains(&theirs.stream_codec) {
+            theirs.stream_codec = Codec::Identity;
+        }
+        Ok(theirs)
+    }
+}
+
+/// A [`Connection`] paired with a cached handshake result.
+///
+/// The handshake runs once, on first use, so capability-gated methods like
+/// [`get_tor_exit_node_list`] can fail fast with [`RequestError::Unsupported`]
+/// instead of sending a request the server cannot answer. Every other method
+/// also runs the handshake first, so a version-incompatible server is caught
+/// before any request is sent instead of a request against it silently
+/// deserializing garbage. These methods report that case as
+/// [`RequestError::Unsupported`]; [`NegotiatedConnection::protocol`] returns
+/// the dedicated [`NegotiationError::IncompatibleVersion`].
+///
+/// Prefer this over calling [`Connection`]'s methods directly whenever the
+/// peer's schema version isn't already known to be compatible.
+///
+/// [`get_tor_exit_node_list`]: NegotiatedConnection::get_tor_exit_node_list
+pub struct NegotiatedConnection {
+    conn: Connection,
+    protocol: OnceCell<NegotiatedProtocol>,
+}
+
+impl NegotiatedConnection {
+    /// Wraps `conn`; the handshake is deferred until the first method call
+    /// or explicit call to [`NegotiatedConnection::protocol`].
+    pub fn new(conn: Connection) -> Self {
+        Self {
+            conn,
+            protocol: OnceCell::new(),
+        }
+    }
+
+    /// Returns the negotiated protocol, performing the handshake on first call.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handshake fails or the server's major protocol
+    /// version is incompatible with this client's.
+    pub async fn protocol(&self) -> Result<&NegotiatedProtocol, NegotiationError> {
+        self.protocol
+            .get_or_try_init(|| self.conn.negotiate())
+            .await
+    }
+
+    /// See [`Connection::get_config`]. Runs the handshake first, so a request
+    /// against a version-incompatible server never reaches the wire.
+    pub async fn get_config(&self) -> Result<String, RequestError> {
+        self.protocol().await?;
+        self.conn.get_config().await
+    }
+
+    /// See [`Connection::get_allow_list`]. Runs the handshake first, so a
+    /// request against a version-incompatible server never reaches the wire.
+    pub async fn get_allow_list(&self) -> Result<HostNetworkGroup, RequestError> {
+        self.protocol().await?;
+        self.conn.get_allow_list().await
+    }
+
+    /// See [`Connection::get_block_list`]. Runs the handshake first, so a
+    /// request against a version-incompatible server never reaches the wire.
+    pub async fn get_block_list(&self) -> Result<HostNetworkGroup, RequestError> {
+        self.protocol().await?;
+        self.conn.get_block_list().await
+    }
+
+    /// See [`Connection::get_data_source`]. Runs the handshake first, so a
+    /// request against a version-incompatible server never reaches the wire.
+    pub async fn get_data_source(&self, key: &DataSourceKey<'_>) -> Result<DataSource, RequestError> {
+        self.protocol().await?;
+        self.conn.get_data_source(key).await
+    }
+
+    /// See [`Connection::get_internal_network_list`]. Runs the handshake
+    /// first, so a request against a version-incompatible server never
+    /// reaches the wire.
+    pub async fn get_internal_network_list(&self) -> Result<HostNetworkGroup, RequestError> {
+        self.protocol().await?;
+        self.conn.get_internal_network_list().await
+    }
+
+    /// See [`Connection::get_tidb_patterns`]. Runs the handshake first, so a
+    /// request against a version-incompatible server never reaches the wire.
+    pub async fn get_tidb_patterns(
+        &self,
+        tidbs: &[(String, String)],
+    ) -> Result<Vec<(String, Option<crate::types::Tidb>)>, RequestError> {
+        self.protocol().await?;
+        self.conn.get_tidb_patterns(tidbs).await
+    }
+
+    /// See [`Connection::get_tor_exit_node_list`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RequestError::Unsupported`] without contacting the server if
+    /// the negotiated capability set doesn't include this method.
+    pub async fn get_tor_exit_node_list(&self) -> Result<Vec<String>, RequestError> {
+        let protocol = self.protocol().await?;
+        if !protocol.supports(Capability::GetTorExitNodeList) {
+            return Err(RequestError::Unsupported);
+        }
+        self.conn.get_tor_exit_node_list().await
+    }
+
+    /// See [`Connection::get_trusted_domain_list`]. Runs the handshake first,
+    /// so a request against a version-incompatible server never reaches the
+    /// wire.
+    pub async fn get_trusted_domain_list(&self) -> Result<Vec<String>, RequestError> {
+        self.protocol().await?;
+        self.conn.get_trusted_domain_list().await
+    }
+
+    /// See [`Connection::get_trusted_user_agent_list`]. Runs the handshake
+    /// first, so a request against a version-incompatible server never
+    /// reaches the wire.
+    pub async fn get_trusted_user_agent_list(&self) -> Result<Vec<String>, RequestError> {
+        self.protocol().await?;
+        self.conn.get_trusted_user_agent_list().await
+    }
+
+    /// See [`Connection::get_pretrained_model`]. Runs the handshake first, so
+    /// a request against a version-incompatible server never reaches the
+    /// wire.
+    pub async fn get_pretrained_model(&self, name: &str) -> Result<Vec<u8>, RequestError> {
+        self.protocol().await?;
+        self.conn.get_pretrained_model(name).await
+    }
+
+    /// See [`Connection::renew_certificate`]. Runs the handshake first, so a
+    /// request against a version-incompatible server never reaches the wire.
+    pub async fn renew_certificate(&self, cert: &[u8]) -> Result<(String, String), RequestError> {
+        self.protocol().await?;
+        self.conn.renew_certificate(cert).await
+    }
+
+    /// See [`Connection::get_pretrained_model_stream`], decompressing each
+    /// chunk with the codec negotiated during the handshake.
+    ///
+    /// Gated behind the `unstable-pretrained-model-stream` feature; see that
+    /// method's docs for why.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handshake or the request fails to send.
+    #[cfg(feature = "unstable-pretrained-model-stream")]
+    pub async fn get_pretrained_model_stream(
+        &self,
+        name: &str,
+    ) -> Result<PretrainedModelStream<impl AsyncRead + Unpin>, RequestError> {
+        let codec = self.protocol().await?.stream_codec;
+        let recv = open_pretrained_model_stream(&self.conn, name).await?;
+        Ok(PretrainedModelStream::new(recv, codec))
+    }
+}
+
+/// Upper bound on a single chunk's body size in [`PretrainedModelStream`].
+///
+/// The length prefix is a server-controlled `u32` (up to ~4 GiB); without
+/// this cap a single oversized prefix would force an allocation of that
+/// size before a single body byte has even arrived, defeating the fixed
+/// memory ceiling streaming exists to provide.
+#[cfg(any(feature = "unstable-pretrained-model-stream", test))]
+const MAX_CHUNK_LEN: usize = 64 * 1024 * 1024;
+
+/// Upper bound on a single chunk's size *after* decompression.
+///
+/// [`MAX_CHUNK_LEN`] only bounds the compressed bytes read off the wire; a
+/// hostile or buggy server can still pack a chunk that expands far past it
+/// once decompressed (a "decompression bomb"). [`Codec::decompress`] enforces
+/// this bound on its output so that cap isn't bypassable just by compressing
+/// the payload.
+const MAX_DECOMPRESSED_CHUNK_LEN: usize = 256 * 1024 * 1024;
+
+/// The read state of a [`PretrainedModelStream`]: either filling the `u32`
+/// length prefix of the next chunk, filling the chunk body, or draining an
+/// already-read chunk body into the caller's buffer.
+#[cfg(any(feature = "unstable-pretrained-model-stream", test))]
+enum ChunkState {
+    ReadingLen { buf: [u8; 4], filled: usize },
+    ReadingBody { buf: Vec<u8>, filled: usize },
+    HaveBody { buf: Vec<u8>, pos: usize },
+    Done,
+}
+
+/// An [`AsyncRead`] over a pretrained model streamed as bounded,
+/// length-delimited chunks, returned by [`Connection::get_pretrained_model_stream`].
+///
+/// Each chunk is decompressed with `codec` as it completes, so a negotiated
+/// compression codec is transparent to callers reading the model bytes.
+#[cfg(any(feature = "unstable-pretrained-model-stream", test))]
+pub struct PretrainedModelStream<R> {
+    recv: R,
+    codec: Codec,
+    state: ChunkState,
+}
+
+#[cfg(any(feature = "unstable-pretrained-model-stream", test))]
+impl<R> PretrainedModelStream<R> {
+    fn new(recv: R, codec: Codec) -> Self {
+        Self {
+            recv,
+            codec,
+            state: ChunkState::ReadingLen {
+                buf: [0; 4],
+                filled: 0,
+            },
+        }
+    }
+}
+
+#[cfg(any(feature = "unstable-pretrained-model-stream", test))]
+impl<R: AsyncRead + Unpin> AsyncRead for PretrainedModelStream<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.state {
+                ChunkState::Done => return Poll::Ready(Ok(())),
+                ChunkState::ReadingLen { buf, filled } => {
+                    let mut read_buf = ReadBuf::new(&mut buf[*filled..]);
+                    match Pin::new(&mut this.recv).poll_read(cx, &mut read_buf) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Ready(Ok(())) => {
+                            let n = read_buf.filled().len();
+                            if n == 0 {
+                                return Poll::Ready(Err(io::Error::new(
+                                    io::ErrorKind::UnexpectedEof,
+                                    "pretrained model stream closed before a complete chunk length prefix",
+                                )));
+                            }
+                            *filled += n;
+                            if *filled == buf.len() {
+                                let len = u32::from_be_bytes(*buf) as usize;
+                                this.state = if len == 0 {
+                                    ChunkState::Done
+                                } else if len > MAX_CHUNK_LEN {
+                                    return Poll::Ready(Err(io::Error::new(
+                                        io::ErrorKind::InvalidData,
+                                        format!(
+                                            "chunk length {len} exceeds the {MAX_CHUNK_LEN}-byte limit"
+                                        ),
+                                    )));
+                                } else {
+                                    ChunkState::ReadingBody {
+                                        buf: vec![0; len],
+                                        filled: 0,
+                                    }
+                                };
+                            }
+                        }
+                    }
+                }
+                ChunkState::ReadingBody { buf, filled } => {
+                    let mut read_buf = ReadBuf::new(&mut buf[*filled..]);
+                    match Pin::new(&mut this.recv).poll_read(cx, &mut read_buf) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Ready(Ok(())) => {
+                            let n = read_buf.filled().len();
+                            if n == 0 {
+                                return Poll::Ready(Err(io::Error::new(
+                                    io::ErrorKind::UnexpectedEof,
+                                    "pretrained model stream closed before the terminating zero-length chunk",
+                                )));
+                            }
+                            *filled += n;
+                            if *filled == buf.len() {
+                                let raw = std::mem::take(buf);
+                                let buf = match this.codec.decompress(raw) {
+                                    Ok(buf) => buf,
+                                    Err(e) => {
+                                        return Poll::Ready(Err(io::Error::new(
+                                            io::ErrorKind::InvalidData,
+                                            e,
+                                        )))
+                                    }
+                                };
+                                this.state = ChunkState::HaveBody { buf, pos: 0 };
+                            }
+                        }
+                    }
+                }
+                ChunkState::HaveBody { buf, pos } => {
+                    let remaining = &buf[*pos..];
+                    if remaining.is_empty() {
+                        // A chunk that decompressed to zero bytes (a valid,
+                        // if wasteful, codec output) has nothing to deliver.
+                        // Move on to the next chunk instead of returning
+                        // Ready(Ok(())) with n = 0, which AsyncRead callers
+                        // read as clean EOF and would truncate the model.
+                        this.state = ChunkState::ReadingLen {
+                            buf: [0; 4],
+                            filled: 0,
+                        };
+                        continue;
+                    }
+                    let n = remaining.len().min(out.remaining());
+                    out.put_slice(&remaining[..n]);
+                    *pos += n;
+                    if *pos == buf.len() {
+                        this.state = ChunkState::ReadingLen {
+                            buf: [0; 4],
+                            filled: 0,
+                        };
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+/// Returns `true` if `data_source` is actually the object `key` asked for,
+/// guarding against a stale or misrouted server response being accepted
+/// blindly.
+fn data_source_matches(data_source: &DataSource, key: &DataSourceKey<'_>) -> bool {
+    match key {
+        DataSourceKey::Id(id) => data_source.id == *id,
+        DataSourceKey::Name(name) => data_source.name == *name,
+    }
+}
+
+/// Per-call overrides for [`request_with_options`]'s default, per-[`server::RequestCode`]
+/// timeout.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestOptions {
+    /// Overrides [`default_timeout`] for this call; `None` keeps the default.
+    pub timeout: Option<Duration>,
+}
+
+/// A request exceeding this duration is logged as slow, even if it still
+/// completes within its timeout.
+const SLOW_REQUEST_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// The timeout applied to a request of `code` when [`RequestOptions::timeout`]
+/// doesn't override it.
+fn default_timeout(code: server::RequestCode) -> Duration {
+    match code {
+        server::RequestCode::GetPretrainedModel => Duration::from_secs(60),
+        server::RequestCode::GetTidbPatterns => Duration::from_secs(30),
+        _ => Duration::from_secs(10),
     }
 }
 
-async fn request<I, O>(conn: &Connection, code: server::RequestCode, input: I) -> io::Result<O>
+/// Sends `input` as the body of request `code` and deserializes the
+/// response, bounded by `options` (or the per-code default from
+/// [`default_timeout`] if `options.timeout` is `None`). Aborts and returns
+/// [`RequestError::Timeout`] if the deadline passes, and logs a `tracing`
+/// warning with the request code and elapsed time whenever the request takes
+/// longer than [`SLOW_REQUEST_THRESHOLD`], whether it completes or times out.
+async fn request_with_options<I, O>(
+    conn: &Connection,
+    code: server::RequestCode,
+    input: I,
+    options: RequestOptions,
+) -> Result<O, RequestError>
 where
     I: Serialize,
     O: DeserializeOwned,
 {
-    let (mut send, mut recv) = conn.open_bi().await?;
-    unary_request(&mut send, &mut recv, u32::from(code), input).await
+    let timeout = options.timeout.unwrap_or_else(|| default_timeout(code));
+    let started = tokio::time::Instant::now();
+    let outcome = tokio::time::timeout(timeout, async {
+        let (mut send, mut recv) = conn.open_bi().await.map_err(classify_request_error)?;
+        unary_request(&mut send, &mut recv, u32::from(code), input)
+            .await
+            .map_err(classify_request_error)
+    })
+    .await;
+
+    let elapsed = started.elapsed();
+    if elapsed > SLOW_REQUEST_THRESHOLD {
+        tracing::warn!(code = u32::from(code), ?elapsed, "slow request");
+    }
+
+    outcome.unwrap_or(Err(RequestError::Timeout))
+}
+
+/// Opens the bi-stream and sends the pretrained-model-stream request,
+/// leaving the caller to read the length-delimited chunk response off the
+/// returned stream. Shared by [`Connection::get_pretrained_model_stream`] and
+/// [`NegotiatedConnection::get_pretrained_model_stream`].
+#[cfg(feature = "unstable-pretrained-model-stream")]
+async fn open_pretrained_model_stream(
+    conn: &Connection,
+    name: &str,
+) -> Result<impl AsyncRead + Unpin, RequestError> {
+    let (mut send, recv) = conn.open_bi().await.map_err(classify_request_error)?;
+    oinq::message::send_request(&mut send, GET_PRETRAINED_MODEL_STREAM_REQUEST_CODE, name)
+        .await
+        .map_err(classify_request_error)?;
+    Ok(recv)
+}
+
+/// Backoff parameters for [`ReconnectingConnection`].
+///
+/// Retries use exponential backoff (`base_backoff * 2^attempt`, capped at
+/// `max_backoff`) plus a small random jitter so that many clients reconnecting
+/// at once don't all retry in lockstep.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+    pub jitter: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            base_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(30),
+            jitter: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_backoff
+            .saturating_mul(1u32.checked_shl(attempt).unwrap_or(u32::MAX));
+        let capped = exp.min(self.max_backoff);
+        if self.jitter.is_zero() {
+            return capped;
+        }
+        // A timestamp-derived jitter is good enough here: we only need to
+        // desynchronize concurrent retries, not produce cryptographic randomness.
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        let jitter_nanos = u64::from(nanos) % u64::try_from(self.jitter.as_nanos()).unwrap_or(1).max(1);
+        capped + Duration::from_nanos(jitter_nanos)
+    }
+}
+
+/// Returns `true` if `err` looks like a transport-level failure (the QUIC
+/// connection was lost, reset, or never came up) rather than an application or
+/// I/O error reported by an otherwise-healthy connection.
+fn is_transport_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::TimedOut
+    )
+}
+
+type BoxConnectFuture = Pin<Box<dyn Future<Output = io::Result<Connection>> + Send>>;
+
+/// A [`Connection`] that transparently reconnects and retries after a
+/// transport failure, instead of failing permanently like a bare `Connection`.
+///
+/// Only idempotent reads (the `get_*` methods) are retried; [`renew_certificate`]
+/// is never retried automatically, since replaying it is not safe by default.
+///
+/// [`renew_certificate`]: ReconnectingConnection::renew_certificate
+pub struct ReconnectingConnection {
+    conn: RwLock<Connection>,
+    connect: Arc<dyn Fn() -> BoxConnectFuture + Send + Sync>,
+    retry_policy: RetryPolicy,
+}
+
+impl ReconnectingConnection {
+    /// Wraps an already-established `conn`, using `connect` to rebuild it (from
+    /// the original endpoint, server name, and certificates) whenever a request
+    /// fails with a transport error.
+    pub fn new<F, Fut>(conn: Connection, connect: F, retry_policy: RetryPolicy) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<Connection>> + Send + 'static,
+    {
+        Self {
+            conn: RwLock::new(conn),
+            connect: Arc::new(move || Box::pin(connect())),
+            retry_policy,
+        }
+    }
+
+    async fn reconnect(&self) -> io::Result<()> {
+        let new_conn = (self.connect)().await?;
+        *self.conn.write().await = new_conn;
+        Ok(())
+    }
+
+    /// Runs `op` against the current inner connection, reconnecting and
+    /// retrying on a transport error when `idempotent` is `true`.
+    async fn call<T, F, Fut>(&self, idempotent: bool, op: F) -> Result<T, RequestError>
+    where
+        F: Fn(&Connection) -> Fut,
+        Fut: Future<Output = Result<T, RequestError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let res = {
+                let conn = self.conn.read().await;
+                op(&conn).await
+            };
+            match res {
+                Ok(v) => return Ok(v),
+                Err(RequestError::Transport(e))
+                    if idempotent && is_transport_error(&e) && attempt < self.retry_policy.max_retries =>
+                {
+                    tokio::time::sleep(self.retry_policy.backoff(attempt)).await;
+                    self.reconnect()
+                        .await
+                        .map_err(RequestError::Transport)?;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// See [`Connection::get_config`].
+    pub async fn get_config(&self) -> Result<String, RequestError> {
+        self.call(true, Connection::get_config).await
+    }
+
+    /// See [`Connection::get_allow_list`].
+    pub async fn get_allow_list(&self) -> Result<HostNetworkGroup, RequestError> {
+        self.call(true, Connection::get_allow_list).await
+    }
+
+    /// See [`Connection::get_block_list`].
+    pub async fn get_block_list(&self) -> Result<HostNetworkGroup, RequestError> {
+        self.call(true, Connection::get_block_list).await
+    }
+
+    /// See [`Connection::get_data_source`].
+    pub async fn get_data_source(&self, key: &DataSourceKey<'_>) -> Result<DataSource, RequestError> {
+        self.call(true, |conn| conn.get_data_source(key)).await
+    }
+
+    /// See [`Connection::get_internal_network_list`].
+    pub async fn get_internal_network_list(&self) -> Result<HostNetworkGroup, RequestError> {
+        self.call(true, Connection::get_internal_network_list).await
+    }
+
+    /// See [`Connection::get_tidb_patterns`].
+    pub async fn get_tidb_patterns(
+        &self,
+        tidbs: &[(String, String)],
+    ) -> Result<Vec<(String, Option<crate::types::Tidb>)>, RequestError> {
+        self.call(true, |conn| conn.get_tidb_patterns(tidbs)).await
+    }
+
+    /// See [`Connection::get_tor_exit_node_list`].
+    pub async fn get_tor_exit_node_list(&self) -> Result<Vec<String>, RequestError> {
+        self.call(true, Connection::get_tor_exit_node_list).await
+    }
+
+    /// See [`Connection::get_trusted_domain_list`].
+    pub async fn get_trusted_domain_list(&self) -> Result<Vec<String>, RequestError> {
+        self.call(true, Connection::get_trusted_domain_list).await
+    }
+
+    /// See [`Connection::get_trusted_user_agent_list`].
+    pub async fn get_trusted_user_agent_list(&self) -> Result<Vec<String>, RequestError> {
+        self.call(true, Connection::get_trusted_user_agent_list).await
+    }
+
+    /// See [`Connection::get_pretrained_model`].
+    pub async fn get_pretrained_model(&self, name: &str) -> Result<Vec<u8>, RequestError> {
+        self.call(true, |conn| conn.get_pretrained_model(name)).await
+    }
+
+    /// See [`Connection::renew_certificate`]. Not retried: a dropped connection
+    /// leaves the caller unsure whether the server already rotated the
+    /// certificate, so replaying this call automatically is not safe.
+    pub async fn renew_certificate(&self, cert: &[u8]) -> Result<(String, String), RequestError> {
+        self.call(false, |conn| conn.renew_certificate(cert)).await
+    }
 }
 
 #[cfg(all(test, feature = "server"))]
@@ -179,6 +1328,41 @@ mod tests {
         test_env.teardown(server_conn);
     }
 
+    #[tokio::test]
+    async fn get_data_source_identity_check() {
+        let test_env = TEST_ENV.lock().await;
+        let (server_conn, client_conn) = test_env.setup().await;
+
+        let handler_conn = server_conn.clone();
+        let server_handle = tokio::spawn(async move {
+            let mut handler = TestServerHandler;
+            let (mut send, mut recv) = handler_conn.as_quinn().accept_bi().await.unwrap();
+            handle(&mut handler, &mut send, &mut recv).await?;
+            Ok(()) as std::io::Result<()>
+        });
+
+        let data_source = client_conn
+            .get_data_source(&DataSourceKey::Id(5))
+            .await
+            .unwrap();
+
+        assert!(super::data_source_matches(&data_source, &DataSourceKey::Id(5)));
+        assert!(super::data_source_matches(
+            &data_source,
+            &DataSourceKey::Name(&data_source.name)
+        ));
+        assert!(!super::data_source_matches(&data_source, &DataSourceKey::Id(6)));
+        assert!(!super::data_source_matches(
+            &data_source,
+            &DataSourceKey::Name("not-the-name")
+        ));
+
+        let server_res = server_handle.await.unwrap();
+        assert!(server_res.is_ok());
+
+        test_env.teardown(server_conn);
+    }
+
     #[tokio::test]
     async fn get_tidb_patterns() {
         use crate::server::RequestCode;
@@ -254,3 +1438,116 @@ mod tests {
         test_env.teardown(server_conn);
     }
 }
+
+#[cfg(test)]
+mod retry_tests {
+    use std::time::Duration;
+
+    use super::RetryPolicy;
+
+    #[test]
+    fn backoff_doubles_then_caps_at_max_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            base_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            jitter: Duration::ZERO,
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(200));
+        assert_eq!(policy.backoff(2), Duration::from_millis(400));
+        assert_eq!(policy.backoff(3), Duration::from_millis(500));
+        assert_eq!(policy.backoff(10), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn backoff_adds_jitter_within_bounds() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            base_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            jitter: Duration::from_millis(50),
+        };
+        for attempt in 0..5 {
+            let backoff = policy.backoff(attempt);
+            let unjittered = (policy.base_backoff * 2u32.pow(attempt)).min(policy.max_backoff);
+            assert!(backoff >= unjittered);
+            assert!(backoff < unjittered + policy.jitter);
+        }
+    }
+}
+
+/// Tests for client-side logic that doesn't need a real server connection,
+/// so they run regardless of the `server` feature.
+#[cfg(test)]
+mod stream_tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{ChunkState, Codec, PretrainedModelStream, MAX_CHUNK_LEN};
+
+    #[tokio::test]
+    async fn reads_chunks_and_stops_at_terminator() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            writer.write_all(&4u32.to_be_bytes()).await.unwrap();
+            writer.write_all(b"abcd").await.unwrap();
+            writer.write_all(&3u32.to_be_bytes()).await.unwrap();
+            writer.write_all(b"efg").await.unwrap();
+            writer.write_all(&0u32.to_be_bytes()).await.unwrap();
+        });
+
+        let mut stream = PretrainedModelStream::new(reader, Codec::Identity);
+        let mut out = Vec::new();
+        stream.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, b"abcdefg");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_chunk_length_without_allocating() {
+        let (mut writer, reader) = tokio::io::duplex(16);
+        let oversized = (MAX_CHUNK_LEN as u32) + 1;
+        tokio::spawn(async move {
+            let _ = writer.write_all(&oversized.to_be_bytes()).await;
+        });
+
+        let mut stream = PretrainedModelStream::new(reader, Codec::Identity);
+        let mut buf = [0u8; 8];
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn closing_without_terminator_is_unexpected_eof_not_clean_end() {
+        let (writer, reader) = tokio::io::duplex(16);
+        drop(writer);
+
+        let mut stream = PretrainedModelStream::new(reader, Codec::Identity);
+        let mut buf = [0u8; 8];
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn empty_chunk_body_is_not_mistaken_for_eof() {
+        // A chunk that decompresses to zero bytes (as a real codec legally
+        // can) must not be reported as a clean end of stream: there's a
+        // terminating zero-length chunk still to come, with real bytes
+        // after the empty one.
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let mut stream = PretrainedModelStream::new(reader, Codec::Identity);
+        stream.state = ChunkState::HaveBody {
+            buf: Vec::new(),
+            pos: 0,
+        };
+
+        tokio::spawn(async move {
+            writer.write_all(&3u32.to_be_bytes()).await.unwrap();
+            writer.write_all(b"abc").await.unwrap();
+            writer.write_all(&0u32.to_be_bytes()).await.unwrap();
+        });
+
+        let mut out = Vec::new();
+        stream.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, b"abc");
+    }
+}